@@ -0,0 +1,72 @@
+//! The BMP encoder.
+
+use std::io::Write;
+
+use crate::ImageError;
+
+const FILE_HEADER_SIZE: u32 = 14;
+const DIB_HEADER_SIZE: u32 = 40;
+
+/// Encoder for BMP (Windows Bitmap) images.
+///
+/// Only 24-bit, uncompressed `BI_RGB` output is supported.
+pub struct BMPEncoder<W> {
+    writer: W,
+}
+
+impl<W: Write> BMPEncoder<W> {
+    /// Create a new encoder that writes to `writer`.
+    pub fn new(writer: W) -> Self {
+        BMPEncoder { writer }
+    }
+
+    /// Encode `width * height` RGB8 pixels (tightly packed, top row first)
+    /// as a 24-bit uncompressed BMP.
+    pub fn encode(&mut self, data: &[u8], width: u32, height: u32) -> Result<(), ImageError> {
+        let expected_len = width as usize * height as usize * 3;
+        if data.len() != expected_len {
+            return Err(ImageError::FormatError(format!(
+                "expected {} bytes of pixel data, got {}",
+                expected_len,
+                data.len()
+            )));
+        }
+
+        let row_stride = ((width as usize * 3 + 3) / 4) * 4;
+        let pixel_data_size = row_stride * height as usize;
+        let file_size = FILE_HEADER_SIZE + DIB_HEADER_SIZE + pixel_data_size as u32;
+        let data_offset = FILE_HEADER_SIZE + DIB_HEADER_SIZE;
+
+        // BITMAPFILEHEADER
+        self.writer.write_all(b"BM")?;
+        self.writer.write_all(&file_size.to_le_bytes())?;
+        self.writer.write_all(&0u16.to_le_bytes())?;
+        self.writer.write_all(&0u16.to_le_bytes())?;
+        self.writer.write_all(&data_offset.to_le_bytes())?;
+
+        // BITMAPINFOHEADER
+        self.writer.write_all(&DIB_HEADER_SIZE.to_le_bytes())?;
+        self.writer.write_all(&(width as i32).to_le_bytes())?;
+        self.writer.write_all(&(height as i32).to_le_bytes())?;
+        self.writer.write_all(&1u16.to_le_bytes())?; // planes
+        self.writer.write_all(&24u16.to_le_bytes())?; // bit count
+        self.writer.write_all(&0u32.to_le_bytes())?; // BI_RGB
+        self.writer.write_all(&(pixel_data_size as u32).to_le_bytes())?;
+        self.writer.write_all(&0i32.to_le_bytes())?; // x pixels per meter
+        self.writer.write_all(&0i32.to_le_bytes())?; // y pixels per meter
+        self.writer.write_all(&0u32.to_le_bytes())?; // colors used
+        self.writer.write_all(&0u32.to_le_bytes())?; // colors important
+
+        // Pixel data, bottom row first, each row padded to a 4-byte boundary.
+        let padding = [0u8; 4];
+        for row in (0..height as usize).rev() {
+            let src = &data[row * width as usize * 3..(row + 1) * width as usize * 3];
+            for pixel in src.chunks_exact(3) {
+                self.writer.write_all(&[pixel[2], pixel[1], pixel[0]])?;
+            }
+            self.writer.write_all(&padding[..row_stride - width as usize * 3])?;
+        }
+
+        Ok(())
+    }
+}
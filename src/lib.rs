@@ -7,7 +7,7 @@
 //!  * <https://en.wikipedia.org/wiki/BMP_file_format>
 //!
 
-pub use crate::decoder::BMPDecoder;
+pub use crate::decoder::{decode_catching, detect_bmp, BMPDecoder, BmpInfo, ColorType, Compression, DetectionScore, Limits};
 pub use crate::encoder::BMPEncoder;
 
 #[derive(Debug)]